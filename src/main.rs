@@ -2,29 +2,35 @@ use csv::{ReaderBuilder, Trim, Writer};
 
 use serde::{Deserialize, Serialize, Serializer};
 
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::env;
 use std::error::Error;
 use std::ffi::OsString;
 use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::ops::{Add, Sub};
 
 /// Application errors.
 #[derive(Debug)]
 enum BankError {
+    InvalidAmount(String),
     MissingAmount,
+    MissingCounterparty,
     Usage,
 }
 
 impl Display for BankError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        let message = match &self {
-            Self::MissingAmount => "Amount missing in transaction CSV",
-            Self::Usage => "Command line usage error",
-        };
-        write!(f, "{}", message)
+        match &self {
+            Self::InvalidAmount(raw) => write!(f, "Invalid amount in transaction CSV: {:?}", raw),
+            Self::MissingAmount => write!(f, "Amount missing in transaction CSV"),
+            Self::MissingCounterparty => write!(f, "Transfer missing a `to` client in transaction CSV"),
+            Self::Usage => write!(f, "Command line usage error"),
+        }
     }
 }
 
@@ -33,21 +39,26 @@ impl Error for BankError {}
 /// Transaction errors.
 #[derive(Debug)]
 enum TxError {
+    AlreadyDisputed,
     InsufficientFunds,
     LockedAccount,
-    NoSuchTransaction,
+    NotDisputed,
     Overflow,
+    UnknownTx(ClientId, TxId),
 }
 
 impl Display for TxError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        let message = match &self {
-            Self::InsufficientFunds => "Insufficient funds",
-            Self::LockedAccount => "Locked account",
-            Self::NoSuchTransaction => "Referenced transaction not found",
-            Self::Overflow => "Numerical overflow",
-        };
-        write!(f, "{}", message)
+        match &self {
+            Self::AlreadyDisputed => write!(f, "Transaction is already under dispute"),
+            Self::InsufficientFunds => write!(f, "Insufficient funds"),
+            Self::LockedAccount => write!(f, "Locked account"),
+            Self::NotDisputed => write!(f, "Transaction is not under dispute"),
+            Self::Overflow => write!(f, "Numerical overflow"),
+            Self::UnknownTx(client, id) => {
+                write!(f, "No transaction {} found for client {}", id, client)
+            }
+        }
     }
 }
 
@@ -82,10 +93,52 @@ impl Sub for Money {
     }
 }
 
-// TODO: Avoid f64, parse the decimal representation directly
-impl From<f64> for Money {
-    fn from(n: f64) -> Self {
-        Self((n * 10_000.0).round() as i64)
+impl Money {
+    /// Parse a decimal amount such as `"1.2345"` or `"-3.5"` straight from its
+    /// CSV text, avoiding the precision loss of a round-trip through `f64`.
+    ///
+    /// Up to four fractional digits are kept; a fifth or later digit is
+    /// truncated rather than rounded.
+    fn parse(raw: &str) -> Result<Self, BankError> {
+        let invalid = || BankError::InvalidAmount(raw.to_string());
+
+        let (negative, unsigned) = match raw.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, raw),
+        };
+
+        let mut parts = unsigned.splitn(2, '.');
+        let int_part = parts.next().ok_or_else(invalid)?;
+        let frac_part = parts.next().unwrap_or("");
+
+        if int_part.is_empty() && frac_part.is_empty() {
+            return Err(invalid());
+        }
+
+        if !int_part.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let int_value: i64 = if int_part.is_empty() {
+            0
+        } else {
+            int_part.parse().map_err(|_| invalid())?
+        };
+
+        if !frac_part.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let mut frac_value: i64 = 0;
+        for i in 0..4 {
+            let digit = frac_part.as_bytes().get(i).map_or(0, |b| (b - b'0') as i64);
+            frac_value = frac_value * 10 + digit;
+        }
+
+        let magnitude = int_value
+            .checked_mul(10_000)
+            .and_then(|n| n.checked_add(frac_value))
+            .ok_or_else(invalid)?;
+
+        Ok(Self(if negative { -magnitude } else { magnitude }))
     }
 }
 
@@ -133,26 +186,39 @@ enum Tx {
         client: ClientId,
         id: TxId,
     },
+    Transfer {
+        from: ClientId,
+        to: ClientId,
+        id: TxId,
+        amount: Money,
+    },
 }
 
 /// Transaction DTO.
+///
+/// `to` is only populated for `"transfer"` rows, which carry the recipient
+/// client in that extra trailing CSV column.
 #[derive(Debug, Deserialize)]
 struct TxRecord {
     #[serde(rename = "type")]
     kind: String,
     client: ClientId,
     tx: TxId,
-    amount: Option<f64>,
+    amount: Option<String>,
+    #[serde(default)]
+    to: Option<ClientId>,
 }
 
 impl TryFrom<TxRecord> for Tx {
     type Error = BankError;
 
     fn try_from(record: TxRecord) -> Result<Self, Self::Error> {
-        let TxRecord { kind, client, tx: id, amount } = record;
+        let TxRecord { kind, client, tx: id, amount, to } = record;
 
-        let amount = amount.map(Money::from)
-            .ok_or(BankError::MissingAmount);
+        let amount = match amount {
+            Some(raw) => Money::parse(&raw),
+            None => Err(BankError::MissingAmount),
+        };
 
         let tx = match kind.as_ref() {
             "deposit" => Tx::Deposit { client, id, amount: amount? },
@@ -160,6 +226,12 @@ impl TryFrom<TxRecord> for Tx {
             "dispute" => Tx::Dispute { client, id },
             "resolve" => Tx::Resolve { client, id },
             "chargeback" => Tx::Chargeback { client, id },
+            "transfer" => Tx::Transfer {
+                from: client,
+                to: to.ok_or(BankError::MissingCounterparty)?,
+                id,
+                amount: amount?,
+            },
             _ => todo!(),
         };
 
@@ -267,14 +339,78 @@ impl From<&Account> for AccountRecord {
     }
 }
 
+/// The dispute lifecycle of a previously processed transaction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum TxState {
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+/// A transaction retained for later dispute/resolve/chargeback processing.
+#[derive(Clone, Copy, Debug)]
+struct StoredTx {
+    amount: Money,
+    state: TxState,
+}
+
+/// Fixed seed the audit hash chain starts from, so replaying the same CSV
+/// from scratch always reproduces the same chain head.
+const AUDIT_GENESIS: u64 = 0;
+
+/// One entry in the tamper-evident audit log: the transaction that was
+/// applied, the resulting balances of the account(s) it touched, and the
+/// hash tying this entry to the one before it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct AuditEntry {
+    tx: String,
+    balances: String,
+    #[serde(serialize_with = "serialize_hash_hex", deserialize_with = "deserialize_hash_hex")]
+    hash: u64,
+}
+
+impl AuditEntry {
+    /// `H(prev_hash || tx || balances)`, using `Hasher` over the fields in
+    /// order so each entry commits to everything before it.
+    fn chain_hash(prev_hash: u64, tx: &str, balances: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        prev_hash.hash(&mut hasher);
+        tx.hash(&mut hasher);
+        balances.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+fn serialize_hash_hex<S: Serializer>(hash: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:016x}", hash))
+}
+
+fn deserialize_hash_hex<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+    u64::from_str_radix(&raw, 16).map_err(serde::de::Error::custom)
+}
+
 /// Holds all the accounts and tracks transactions.
 #[derive(Debug, Default)]
 struct Bank {
     accounts: HashMap<ClientId, Account>,
-    amounts: HashMap<TxId, Money>,
+    amounts: HashMap<(ClientId, TxId), StoredTx>,
+    audit: Option<Vec<AuditEntry>>,
 }
 
 impl Bank {
+    /// Turn on the hash-chained audit log; every transaction applied from
+    /// this point on is recorded in [`Bank::audit_log`].
+    fn enable_audit(&mut self) {
+        self.audit.get_or_insert_with(Vec::new);
+    }
+
+    /// The recorded audit entries, in processing order, if auditing is on.
+    fn audit_log(&self) -> &[AuditEntry] {
+        self.audit.as_deref().unwrap_or(&[])
+    }
+
     /// Look up an account by the client's ID number.
     fn account(&mut self, client: ClientId) -> &mut Account {
         self.accounts
@@ -282,38 +418,158 @@ impl Bank {
             .or_insert_with(|| Account::new(client))
     }
 
-    /// Get the amount associated with a previous transaction.
-    fn amount(&self, id: TxId) -> TxResult<Money> {
-        self.amounts
-            .get(&id)
-            .copied()
-            .ok_or(TxError::NoSuchTransaction)
+    /// Mark a previously processed transaction as disputed, returning its amount.
+    ///
+    /// The transaction must have been deposited by `client`; disputing someone
+    /// else's transaction ID is rejected as if it didn't exist.
+    fn dispute_tx(&mut self, client: ClientId, id: TxId) -> TxResult<Money> {
+        let stored = self
+            .amounts
+            .get_mut(&(client, id))
+            .ok_or(TxError::UnknownTx(client, id))?;
+        if stored.state != TxState::Processed {
+            return Err(TxError::AlreadyDisputed);
+        }
+        stored.state = TxState::Disputed;
+        Ok(stored.amount)
     }
 
-    /// Process a single transaction.
+    /// Transition a disputed transaction belonging to `client` to `state`,
+    /// returning its amount.
+    fn settle_dispute(&mut self, client: ClientId, id: TxId, state: TxState) -> TxResult<Money> {
+        let stored = self
+            .amounts
+            .get_mut(&(client, id))
+            .ok_or(TxError::UnknownTx(client, id))?;
+        if stored.state != TxState::Disputed {
+            return Err(TxError::NotDisputed);
+        }
+        stored.state = state;
+        Ok(stored.amount)
+    }
+
+    /// Process a single transaction, extending the audit chain if enabled.
     fn process(&mut self, tx: Tx) -> TxResult {
-        match &tx {
-            &Tx::Deposit { client, id, amount } => {
+        let audit_ctx = self
+            .audit
+            .is_some()
+            .then(|| (format!("{:?}", tx), Self::affected_clients(&tx)));
+
+        let result = self.apply(tx);
+
+        if result.is_ok() {
+            if let Some((tx_repr, clients)) = audit_ctx {
+                self.record_audit(&tx_repr, &clients);
+            }
+        }
+
+        result
+    }
+
+    /// Apply a single transaction's effect on the accounts and tx ledger.
+    fn apply(&mut self, tx: Tx) -> TxResult {
+        match tx {
+            Tx::Deposit { client, id, amount } => {
                 self.account(client).deposit(amount)?;
-                self.amounts.insert(id, amount);
+                self.amounts.insert((client, id), StoredTx { amount, state: TxState::Processed });
                 Ok(())
             }
-            &Tx::Withdrawal { client, id: _, amount } => {
+            Tx::Withdrawal { client, id: _, amount } => {
                 self.account(client).withdraw(amount)
             }
-            &Tx::Dispute { client, id } => {
-                let amount = self.amount(id)?;
+            Tx::Dispute { client, id } => {
+                let amount = self.dispute_tx(client, id)?;
                 self.account(client).dispute(amount)
             }
-            &Tx::Resolve { client, id } => {
-                let amount = self.amount(id)?;
+            Tx::Resolve { client, id } => {
+                let amount = self.settle_dispute(client, id, TxState::Resolved)?;
                 self.account(client).resolve(amount)
             }
-            &Tx::Chargeback { client, id } => {
-                let amount = self.amount(id)?;
+            Tx::Chargeback { client, id } => {
+                let amount = self.settle_dispute(client, id, TxState::ChargedBack)?;
                 self.account(client).chargeback(amount)
             }
+            Tx::Transfer { from, to, id: _id, amount } => {
+                {
+                    let sender = self.account(from);
+                    sender.check_unlocked()?;
+                    if sender.available < amount {
+                        return Err(TxError::InsufficientFunds);
+                    }
+                }
+
+                // Validate both legs before mutating either account, so a
+                // deposit-side failure (e.g. overflowing the recipient)
+                // never leaves the sender debited with nowhere for the
+                // funds to land.
+                let recipient = self.account(to);
+                recipient.check_unlocked()?;
+                (recipient.available + amount)?;
+
+                self.account(from).withdraw(amount)?;
+                self.account(to).deposit(amount)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// The client accounts whose balances a transaction can change.
+    fn affected_clients(tx: &Tx) -> Vec<ClientId> {
+        match *tx {
+            Tx::Deposit { client, .. }
+            | Tx::Withdrawal { client, .. }
+            | Tx::Dispute { client, .. }
+            | Tx::Resolve { client, .. }
+            | Tx::Chargeback { client, .. } => vec![client],
+            Tx::Transfer { from, to, .. } => vec![from, to],
+        }
+    }
+
+    /// A deterministic snapshot of one account's balances, for audit hashing.
+    fn account_snapshot(&self, client: ClientId) -> String {
+        match self.accounts.get(&client) {
+            Some(account) => format!(
+                "{}:{}:{}:{}",
+                account.client_id, account.available, account.held, account.locked
+            ),
+            None => format!("{}:none", client),
+        }
+    }
+
+    /// Extend the audit chain with an entry covering `tx_repr`, hashing in
+    /// the post-transaction balances of `clients`.
+    fn record_audit(&mut self, tx_repr: &str, clients: &[ClientId]) {
+        let balances = clients
+            .iter()
+            .map(|&client| self.account_snapshot(client))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let prev_hash = self
+            .audit
+            .as_ref()
+            .and_then(|log| log.last())
+            .map(|entry| entry.hash)
+            .unwrap_or(AUDIT_GENESIS);
+        let hash = AuditEntry::chain_hash(prev_hash, tx_repr, &balances);
+
+        if let Some(log) = self.audit.as_mut() {
+            log.push(AuditEntry { tx: tx_repr.to_string(), balances, hash });
+        }
+    }
+
+    /// Recompute `entries`' hash chain from `seed` and confirm each entry's
+    /// hash is exactly `H(prev_hash || tx || balances)` for the entry before
+    /// it, proving the log hasn't been tampered with or reordered.
+    fn verify(entries: &[AuditEntry], seed: u64) -> bool {
+        let mut prev_hash = seed;
+        for entry in entries {
+            if AuditEntry::chain_hash(prev_hash, &entry.tx, &entry.balances) != entry.hash {
+                return false;
+            }
+            prev_hash = entry.hash;
         }
+        true
     }
 
     /// Iterate over all the accounts.
@@ -324,11 +580,14 @@ impl Bank {
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args: Vec<OsString> = env::args_os().collect();
-    if args.len() != 2 {
+    if args.len() != 2 && args.len() != 3 {
         Err(BankError::Usage)?;
     }
 
     let mut bank = Bank::default();
+    if args.get(2).is_some() {
+        bank.enable_audit();
+    }
 
     let mut reader = ReaderBuilder::new()
         .flexible(true)
@@ -349,6 +608,30 @@ fn main() -> Result<(), Box<dyn Error>> {
     for account in bank.accounts() {
         writer.serialize(AccountRecord::from(account))?;
     }
+    writer.flush()?;
+
+    if let Some(audit_path) = args.get(2) {
+        let mut audit_writer = Writer::from_writer(File::create(audit_path)?);
+        for entry in bank.audit_log() {
+            audit_writer.serialize(entry)?;
+        }
+        audit_writer.flush()?;
+
+        // Read the sidecar back and replay the chain, the same way a third
+        // party auditing this run would, to prove it wasn't tampered with
+        // on the way to disk.
+        let mut audit_reader = ReaderBuilder::new().trim(Trim::All).from_path(audit_path)?;
+        let replayed: Vec<AuditEntry> = audit_reader
+            .deserialize()
+            .collect::<Result<_, _>>()?;
+
+        let head = replayed.last().map(|entry| entry.hash).unwrap_or(AUDIT_GENESIS);
+        if Bank::verify(&replayed, AUDIT_GENESIS) {
+            eprintln!("audit chain head: {:016x} (verified)", head);
+        } else {
+            eprintln!("audit chain head: {:016x} (VERIFICATION FAILED)", head);
+        }
+    }
 
     Ok(())
 }
@@ -365,4 +648,136 @@ mod tests {
         assert_eq!(Money(12_345).to_string(), "1.2345");
         assert_eq!(Money(-12_345).to_string(), "-1.2345");
     }
+
+    #[test]
+    fn test_dispute_lifecycle() {
+        let mut bank = Bank::default();
+        bank.process(Tx::Deposit { client: 1, id: 1, amount: Money(10_000) }).unwrap();
+        bank.process(Tx::Deposit { client: 1, id: 2, amount: Money(5_000) }).unwrap();
+
+        // Disputing twice in a row is rejected the second time.
+        bank.process(Tx::Dispute { client: 1, id: 1 }).unwrap();
+        let err = bank.process(Tx::Dispute { client: 1, id: 1 });
+        assert!(matches!(err, Err(TxError::AlreadyDisputed)));
+
+        // Resolving clears the dispute; once Resolved, it can't be resolved,
+        // charged back, or disputed again.
+        bank.process(Tx::Resolve { client: 1, id: 1 }).unwrap();
+        let err = bank.process(Tx::Resolve { client: 1, id: 1 });
+        assert!(matches!(err, Err(TxError::NotDisputed)));
+        let err = bank.process(Tx::Chargeback { client: 1, id: 1 });
+        assert!(matches!(err, Err(TxError::NotDisputed)));
+        let err = bank.process(Tx::Dispute { client: 1, id: 1 });
+        assert!(matches!(err, Err(TxError::AlreadyDisputed)));
+
+        // A chargeback with no prior dispute is rejected on a fresh tx too.
+        let err = bank.process(Tx::Chargeback { client: 1, id: 2 });
+        assert!(matches!(err, Err(TxError::NotDisputed)));
+
+        // Once disputed, a chargeback is allowed and locks the account.
+        bank.process(Tx::Dispute { client: 1, id: 2 }).unwrap();
+        bank.process(Tx::Chargeback { client: 1, id: 2 }).unwrap();
+        assert!(bank.account(1).locked);
+    }
+
+    #[test]
+    fn test_money_parse() {
+        assert_eq!(Money::parse("0").unwrap(), Money(0));
+        assert_eq!(Money::parse("1.2345").unwrap(), Money(12_345));
+        assert_eq!(Money::parse("-1.2345").unwrap(), Money(-12_345));
+        assert_eq!(Money::parse("2.742").unwrap(), Money(27_420));
+        assert_eq!(Money::parse(".5").unwrap(), Money(5_000));
+        assert_eq!(Money::parse("3.123456").unwrap(), Money(31_234));
+
+        assert!(Money::parse("").is_err());
+        assert!(Money::parse("-").is_err());
+        assert!(Money::parse("1.2x").is_err());
+        assert!(Money::parse("abc").is_err());
+        assert!(Money::parse("--5").is_err());
+        assert!(Money::parse("-+5").is_err());
+    }
+
+    #[test]
+    fn test_dispute_is_scoped_to_the_depositing_client() {
+        let mut bank = Bank::default();
+        bank.process(Tx::Deposit { client: 1, id: 1, amount: Money(10_000) }).unwrap();
+
+        // Client 2 can't dispute client 1's deposit, even though the tx id exists.
+        let err = bank.process(Tx::Dispute { client: 2, id: 1 });
+        assert!(matches!(err, Err(TxError::UnknownTx(2, 1))));
+        assert_eq!(bank.account(1).held, Money(0));
+
+        // The rightful owner can still dispute it.
+        bank.process(Tx::Dispute { client: 1, id: 1 }).unwrap();
+        assert_eq!(bank.account(1).held, Money(10_000));
+
+        // Resolve/chargeback are scoped the same way.
+        let err = bank.process(Tx::Resolve { client: 2, id: 1 });
+        assert!(matches!(err, Err(TxError::UnknownTx(2, 1))));
+        let err = bank.process(Tx::Chargeback { client: 2, id: 1 });
+        assert!(matches!(err, Err(TxError::UnknownTx(2, 1))));
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut bank = Bank::default();
+        bank.process(Tx::Deposit { client: 1, id: 1, amount: Money(10_000) }).unwrap();
+
+        // Successful transfer moves funds from sender to recipient.
+        bank.process(Tx::Transfer { from: 1, to: 2, id: 2, amount: Money(4_000) }).unwrap();
+        assert_eq!(bank.account(1).available, Money(6_000));
+        assert_eq!(bank.account(2).available, Money(4_000));
+
+        // Insufficient funds leaves both accounts untouched.
+        let err = bank.process(Tx::Transfer { from: 1, to: 2, id: 3, amount: Money(1_000_000) });
+        assert!(matches!(err, Err(TxError::InsufficientFunds)));
+        assert_eq!(bank.account(1).available, Money(6_000));
+        assert_eq!(bank.account(2).available, Money(4_000));
+
+        // A locked sender can't send funds.
+        bank.account(1).locked = true;
+        let err = bank.process(Tx::Transfer { from: 1, to: 2, id: 4, amount: Money(1_000) });
+        assert!(matches!(err, Err(TxError::LockedAccount)));
+        assert_eq!(bank.account(1).available, Money(6_000));
+        bank.account(1).locked = false;
+
+        // A locked recipient can't receive funds either.
+        bank.account(2).locked = true;
+        let err = bank.process(Tx::Transfer { from: 1, to: 2, id: 5, amount: Money(1_000) });
+        assert!(matches!(err, Err(TxError::LockedAccount)));
+        assert_eq!(bank.account(1).available, Money(6_000));
+        assert_eq!(bank.account(2).available, Money(4_000));
+    }
+
+    #[test]
+    fn test_transfer_atomic_on_deposit_overflow() {
+        let mut bank = Bank::default();
+        bank.process(Tx::Deposit { client: 1, id: 1, amount: Money(1_000) }).unwrap();
+        // Fill client 2 right up to the edge of an overflow on the next deposit.
+        bank.account(2).available = Money(i64::MAX - 100);
+
+        let err = bank.process(Tx::Transfer { from: 1, to: 2, id: 2, amount: Money(1_000) });
+        assert!(matches!(err, Err(TxError::Overflow)));
+
+        // The sender must not have been debited since the deposit leg failed.
+        assert_eq!(bank.account(1).available, Money(1_000));
+    }
+
+    #[test]
+    fn test_audit_chain() {
+        let mut bank = Bank::default();
+        bank.enable_audit();
+
+        bank.process(Tx::Deposit { client: 1, id: 1, amount: Money(10_000) }).unwrap();
+        bank.process(Tx::Deposit { client: 2, id: 2, amount: Money(5_000) }).unwrap();
+        bank.process(Tx::Transfer { from: 1, to: 2, id: 3, amount: Money(4_000) }).unwrap();
+
+        let log = bank.audit_log();
+        assert_eq!(log.len(), 3);
+        assert!(Bank::verify(log, AUDIT_GENESIS));
+
+        let mut tampered = log.to_vec();
+        tampered[1].balances = "tampered".to_string();
+        assert!(!Bank::verify(&tampered, AUDIT_GENESIS));
+    }
 }